@@ -0,0 +1,296 @@
+//! A futures-based adapter over the reactor so arbitrary `AsRawFd` handles
+//! can be `.await`ed instead of only driven through `register`'s callback
+//! style, mirroring smol's `Async<T>`.
+
+use crate::{EpollEventLoop, Registration, Token, READABLE, WRITABLE, ONESHOT};
+use crate::sys;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::os::fd::AsRawFd;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
+
+// Registration tokens handed out by `Async` are never looked up by the
+// reactor itself (dispatch keys off the fd), so a bare counter is enough
+// to give each one something unique to carry.
+fn next_token() -> Token {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    Token(NEXT.fetch_add(1, Ordering::SeqCst))
+}
+
+// A direction's parked waker, plus whether it's been fired since the task
+// last checked: `poll_readable`/`poll_writable` only have this flag to go
+// on to decide whether to resolve, since the actual readiness bits aren't
+// trustworthy per-direction (see `wake` below).
+#[derive(Default)]
+struct WakerSlot {
+    waker: Option<Waker>,
+    ready: bool,
+}
+
+struct WakerSlots {
+    readable: Mutex<WakerSlot>,
+    writable: Mutex<WakerSlot>,
+}
+
+impl WakerSlots {
+    // `Async::new` registers a single fd for `READABLE | WRITABLE` under
+    // one shared `ONESHOT` registration, so whichever direction fires
+    // first disarms both: the kernel has no notion of a readable-only or
+    // writable-only one-shot here. Waking only the waker matching
+    // `readiness`'s bits would strand whichever task is parked on the
+    // other direction -- it already re-armed believing it was still
+    // watched, so nothing would ever wake it again. Mark both directions
+    // ready and wake both parked wakers regardless of which bits fired;
+    // `poll_readable`/`poll_writable` re-arm every time they park, so a
+    // spuriously woken task just retries its op, sees `WouldBlock` again
+    // and re-parks.
+    fn wake(&self, _readiness: sys::Readiness) {
+        let mut readable = self.readable.lock().unwrap();
+        readable.ready = true;
+        if let Some(waker) = readable.waker.take() {
+            waker.wake();
+        }
+        drop(readable);
+
+        let mut writable = self.writable.lock().unwrap();
+        writable.ready = true;
+        if let Some(waker) = writable.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Wraps an `AsRawFd` handle with one-shot readiness registration so it
+/// can be polled from `async`/`await` code. A readiness event disarms the
+/// registration; `poll_readable`/`poll_writable` re-arm it each time they
+/// park, so a task only has to park on `readable()`/`writable()` again
+/// after it sees `WouldBlock`.
+pub struct Async<'r, 'a: 'r, T: AsRawFd> {
+    io: T,
+    registration: Registration<'r, 'a>,
+    wakers: Arc<WakerSlots>,
+}
+
+impl<'r, 'a: 'r, T: AsRawFd> Async<'r, 'a, T> {
+    pub fn new(event_loop: &'r EpollEventLoop<'a>, io: T) -> io::Result<Async<'r, 'a, T>> {
+        let wakers = Arc::new(WakerSlots {
+            readable: Mutex::new(WakerSlot::default()),
+            writable: Mutex::new(WakerSlot::default()),
+        });
+        let on_event_wakers = wakers.clone();
+        let fd = io.as_raw_fd();
+
+        let registration = event_loop.register(
+            fd,
+            next_token(),
+            READABLE | WRITABLE,
+            ONESHOT,
+            move |readiness: sys::Readiness| on_event_wakers.wake(readiness),
+        )?;
+
+        Ok(Async { io: io, registration: registration, wakers: wakers })
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    // One-shot registrations disarm themselves once they fire; re-arm
+    // before parking so a readiness change while nothing was polling
+    // isn't missed.
+    fn rearm(&self) -> io::Result<()> {
+        self.registration.event_loop().reregister(
+            self.registration.fd(), next_token(), READABLE | WRITABLE, ONESHOT,
+        )
+    }
+
+    pub fn poll_readable(&self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let mut slot = self.wakers.readable.lock().unwrap();
+        if mem::replace(&mut slot.ready, false) {
+            return Poll::Ready(Ok(()));
+        }
+        slot.waker = Some(cx.waker().clone());
+        drop(slot);
+
+        if let Err(e) = self.rearm() {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Pending
+    }
+
+    pub fn poll_writable(&self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let mut slot = self.wakers.writable.lock().unwrap();
+        if mem::replace(&mut slot.ready, false) {
+            return Poll::Ready(Ok(()));
+        }
+        slot.waker = Some(cx.waker().clone());
+        drop(slot);
+
+        if let Err(e) = self.rearm() {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Pending
+    }
+
+    pub fn readable<'b>(&'b self) -> Readable<'r, 'a, 'b, T> {
+        Readable { io: self }
+    }
+
+    pub fn writable<'b>(&'b self) -> Writable<'r, 'a, 'b, T> {
+        Writable { io: self }
+    }
+
+    /// Retry `op` until it succeeds or fails with something other than
+    /// `WouldBlock`, awaiting readability in between attempts.
+    pub async fn read_with<R, F>(&self, mut op: F) -> io::Result<R>
+            where F: FnMut(&T) -> io::Result<R> {
+        loop {
+            match op(&self.io) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.readable().await?;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    /// Retry `op` until it succeeds or fails with something other than
+    /// `WouldBlock`, awaiting writability in between attempts.
+    pub async fn write_with<R, F>(&self, mut op: F) -> io::Result<R>
+            where F: FnMut(&T) -> io::Result<R> {
+        loop {
+            match op(&self.io) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.writable().await?;
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+pub struct Readable<'r, 'a: 'r, 'b, T: AsRawFd> {
+    io: &'b Async<'r, 'a, T>,
+}
+
+impl<'r, 'a: 'r, 'b, T: AsRawFd> Future for Readable<'r, 'a, 'b, T> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.io.poll_readable(cx)
+    }
+}
+
+pub struct Writable<'r, 'a: 'r, 'b, T: AsRawFd> {
+    io: &'b Async<'r, 'a, T>,
+}
+
+impl<'r, 'a: 'r, 'b, T: AsRawFd> Future for Writable<'r, 'a, 'b, T> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.io.poll_writable(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EventLoop;
+    use std::task::Wake;
+    use std::thread;
+    use std::time::Duration;
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    // A minimal stand-in for a real executor: park the calling thread
+    // between polls instead of spinning, and rely on `Wake::unpark` to
+    // resume it. Good enough to drive `readable()`/`writable()` to
+    // completion in a test without pulling in an async runtime dependency.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(value) => return value,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    struct OwnedSocket(libc::c_int);
+
+    impl AsRawFd for OwnedSocket {
+        fn as_raw_fd(&self) -> libc::c_int {
+            self.0
+        }
+    }
+
+    impl Drop for OwnedSocket {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.0); }
+        }
+    }
+
+    fn make_socketpair() -> (OwnedSocket, OwnedSocket) {
+        let mut fds: [libc::c_int; 2] = [0, 0];
+        let result = unsafe {
+            libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr())
+        };
+        assert_eq!(result, 0, "socketpair() failed: {}", io::Error::last_os_error());
+        (OwnedSocket(fds[0]), OwnedSocket(fds[1]))
+    }
+
+    #[test]
+    fn oneshot_registration_wakes_both_readable_and_writable() {
+        let event_loop = crate::new().unwrap();
+        let (a, b) = make_socketpair();
+        let io = Async::new(&event_loop, a).unwrap();
+
+        thread::scope(|scope| {
+            scope.spawn(|| event_loop.run());
+
+            // Both directions park on the same shared one-shot
+            // registration; only one kernel event will ever fire for it.
+            let reader = scope.spawn(|| block_on(io.readable()));
+            let writer = scope.spawn(|| block_on(io.writable()));
+
+            // Give both tasks a chance to register their wakers and park
+            // before `b` makes `a` readable.
+            thread::sleep(Duration::from_millis(50));
+
+            let byte = [1u8];
+            let written = unsafe {
+                libc::write(b.as_raw_fd(), byte.as_ptr() as *const libc::c_void, 1)
+            };
+            assert_eq!(written, 1, "write() failed: {}", io::Error::last_os_error());
+
+            // If only the reader's waker fired (the bug fixed in the
+            // commit that introduced `wake`'s current behavior), the
+            // writer would hang here forever.
+            reader.join().unwrap().unwrap();
+            writer.join().unwrap().unwrap();
+
+            event_loop.stop();
+        });
+    }
+}
@@ -1,10 +1,13 @@
-extern crate nix;
-extern crate libc;
+use crate::sys;
+use crate::sys::Backend;
+use crate::sys::SysOwnedFd;
 use std::boxed::Box;
 use std::collections;
+use std::io;
 use std::mem;
 use std::sync::Mutex;
 use std::sync::atomic;
+use std::time::{Duration, Instant};
 
 
 pub trait EventLoop {
@@ -13,81 +16,84 @@ pub trait EventLoop {
 }
 
 
-struct EpollHandlerData<'a> {
-    on_event: Box<FnMut(nix::sys::epoll::EpollEventKind) + Send + Sync + 'a>,
-}
+/// Opaque key a caller attaches to a registration. It is stored with the
+/// registration but is not passed to `on_event`; events are dispatched by fd.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Token(pub usize);
 
 
-struct WrappedFd {
-    pub fd: libc::c_int,
-}
-
-impl WrappedFd {
-    fn new(fd: libc::c_int) -> WrappedFd {
-        WrappedFd {
-            fd: fd,
-        }
+bitflags! {
+    /// The readiness a caller wants to be notified about for a registered
+    /// fd. The selector backend (see `sys`) translates these into
+    /// whatever the OS's readiness flags are (`EPOLLIN`/`EPOLLOUT` on
+    /// Linux, `EVFILT_READ`/`EVFILT_WRITE` on the BSDs, ...).
+    pub flags Interest: u32 {
+        const READABLE = 0b01,
+        const WRITABLE = 0b10,
     }
 }
 
-impl Drop for WrappedFd {
-    fn drop(&mut self) {
-        unsafe { libc::close(self.fd) };
+
+bitflags! {
+    /// Readiness delivery mode for a registration, layered on top of
+    /// `Interest`.
+    ///
+    /// `EDGE_TRIGGERED` asks for edge-triggered semantics (`EPOLLET` on
+    /// Linux, `EV_CLEAR` on the BSDs): `on_event` is only called when the
+    /// fd transitions to ready, not for as long as it stays ready, so the
+    /// callback must drain the fd (read/write until `EWOULDBLOCK`) or risk
+    /// missing readiness that doesn't re-trigger.
+    ///
+    /// `ONESHOT` asks for one-shot semantics (`EPOLLONESHOT` on Linux,
+    /// `EV_ONESHOT` on the BSDs): after one event is delivered, the
+    /// registration is disarmed (both in the kernel and in `fd_data`)
+    /// until the caller re-arms it with `reregister`.
+    pub flags Trigger: u32 {
+        const EDGE_TRIGGERED = 0b01,
+        const ONESHOT = 0b10,
     }
 }
 
 
-struct EpollEventLoop<'a> {
-	epoll_fd: WrappedFd,
-    fd_data: collections::HashMap<libc::c_int, EpollHandlerData<'a>>,
-	wakeup_fd: WrappedFd,
-	stop: atomic::AtomicBool,
-    // Calls to run on wakeup.
-    pending_calls: Mutex<collections::VecDeque<Box<FnMut() + Send + 'a>>>,
+struct EpollHandlerData<'a> {
+    token: Token,
+    interest: Interest,
+    trigger: Trigger,
+    on_event: Box<dyn FnMut(sys::Readiness) + Send + Sync + 'a>,
 }
 
 
-fn only_nix_sys_err<T>(result: nix::NixResult<T>)
-        -> Result<T, nix::errno::Errno> {
-    match result {
-        Ok(v) => Ok(v),
-        Err(e) => match e {
-            nix::NixError::Sys(errno) => Err(errno),
-            _ => panic!(
-                "Got a NixError::InvalidPath where I wasn't expecting one."
-            ),
-        }
-    }
+pub struct EpollEventLoop<'a> {
+	epoll_fd: sys::SelectorFd,
+    fd_data: Mutex<collections::HashMap<libc::c_int, EpollHandlerData<'a>>>,
+	wakeup_fd: sys::SelectorFd,
+	stop: atomic::AtomicBool,
+    // Calls to run on wakeup.
+    pending_calls: Mutex<collections::VecDeque<Box<dyn FnMut() + Send + 'a>>>,
+    // Scheduled callbacks, keyed by (deadline, id) so ties at the same
+    // deadline still order deterministically by insertion.
+    timers: Mutex<collections::BTreeMap<(Instant, u64), Box<dyn FnMut() + Send + 'a>>>,
+    next_timer_id: atomic::AtomicUsize,
 }
 
 
-pub fn new<'a>() -> Result<EpollEventLoop<'a>, nix::errno::Errno> {
-	let epoll_fd = WrappedFd::new(try!(
-        only_nix_sys_err(nix::sys::epoll::epoll_create())
-    ));
-
-    let wakeup_fd = WrappedFd::new(try!(
-        only_nix_sys_err(
-            nix::sys::eventfd::eventfd(0, nix::sys::eventfd::EFD_NONBLOCK)
-        )
-    ));
-
-    try!(only_nix_sys_err(nix::sys::epoll::epoll_ctl(
-        epoll_fd.fd,
-        nix::sys::epoll::EpollOp::EpollCtlAdd,
-        wakeup_fd.fd,
-        &nix::sys::epoll::EpollEvent {
-            events: nix::sys::epoll::EPOLLIN,
-            data: wakeup_fd.fd as u64,
-        },
-    )));
+pub fn new<'a>() -> io::Result<EpollEventLoop<'a>> {
+    let selector_fd = sys::SysBackend::create_selector()?;
+    // Safe: `create_selector` just returned a freshly-opened fd that
+    // nothing else owns yet.
+    let epoll_fd = unsafe { sys::SelectorFd::from_raw(selector_fd) };
+    let wakeup_fd = unsafe {
+        sys::SelectorFd::from_raw(sys::SysBackend::create_wakeup(epoll_fd.as_raw())?)
+    };
 
 	Ok(EpollEventLoop {
 		epoll_fd: epoll_fd,
 		wakeup_fd: wakeup_fd,
 		stop: atomic::AtomicBool::new(false),
-        fd_data: collections::HashMap::new(),
+        fd_data: Mutex::new(collections::HashMap::new()),
         pending_calls: Mutex::new(collections::VecDeque::new()),
+        timers: Mutex::new(collections::BTreeMap::new()),
+        next_timer_id: atomic::AtomicUsize::new(0),
 	})
 }
 
@@ -111,36 +117,124 @@ impl<'a> EpollEventLoop<'a> {
     }
 
     fn single_loop(&self) {
-        // This gets initialized by the epoll_wait call.
-        // Note that only a portion of the array may get initialized.
-        let mut events: [nix::sys::epoll::EpollEvent; 16] = unsafe {
-            mem::uninitialized()
+        self.fire_elapsed_timers();
+        let timeout = self.next_epoll_timeout_millis();
+
+        let mut events: [sys::Event; 16] = unsafe { mem::zeroed() };
+        let result = sys::SysBackend::wait(self.epoll_fd.as_raw(), &mut events, timeout);
+        let number_of_events = match result {
+            Ok(size) => size,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted
+                || e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(_) => panic!("Unknown error after waiting for events."),
         };
-        let result = only_nix_sys_err(
-            nix::sys::epoll::epoll_wait(self.epoll_fd.fd, &mut events, 0)
-        );
-        let number_of_events: usize;
-        match result {
-            Ok(size) => number_of_events = size,
-            Err(errno) => {
-                if errno == nix::errno::EINTR {
-                    return
+
+        for event in events.iter().take(number_of_events) {
+            if event.fd == self.wakeup_fd.as_raw() {
+                self.handle_wakeup_fd();
+            } else {
+                self.dispatch_fd_event(event.fd, event.readiness);
+            }
+        }
+    }
+
+    fn dispatch_fd_event(&self, fd: libc::c_int, readiness: sys::Readiness) {
+        // Pull the callback out from behind the lock before invoking it,
+        // so that an `on_event` which itself calls `reregister`/
+        // `deregister` (e.g. to re-arm a one-shot registration) doesn't
+        // deadlock on `fd_data`.
+        let no_op: Box<dyn FnMut(sys::Readiness) + Send + Sync + 'a> = Box::new(|_| {});
+        let mut callback = {
+            let mut fd_data = self.fd_data.lock().unwrap();
+            match fd_data.get_mut(&fd) {
+                Some(data) => {
+                    if data.trigger.contains(ONESHOT) {
+                        // The kernel already disarmed its side; keep our
+                        // bookkeeping in sync until `reregister` re-arms it.
+                        data.interest = Interest::empty();
+                    }
+                    Some(mem::replace(&mut data.on_event, no_op))
+                },
+                None => None,
+            }
+        };
+
+        if let Some(ref mut callback) = callback {
+            callback(readiness);
+        }
+
+        if let Some(callback) = callback {
+            if let Some(data) = self.fd_data.lock().unwrap().get_mut(&fd) {
+                data.on_event = callback;
+            }
+        }
+    }
+
+    fn fire_elapsed_timers(&self) {
+        loop {
+            let due = {
+                let mut timers = self.timers.lock().unwrap();
+                let key = match timers.keys().next() {
+                    Some(&key) if key.0 <= Instant::now() => Some(key),
+                    _ => None,
+                };
+                key.and_then(|key| timers.remove(&key))
+            };
+            match due {
+                Some(mut callback) => callback(),
+                None => break,
+            }
+        }
+    }
+
+    // The milliseconds the selector's `wait` should block for: 0 if there
+    // is work already queued up to run, the time until the next timer
+    // deadline if one is scheduled, or -1 to block indefinitely.
+    fn next_epoll_timeout_millis(&self) -> libc::c_int {
+        if ! self.pending_calls.lock().unwrap().is_empty() {
+            return 0;
+        }
+
+        match self.timers.lock().unwrap().keys().next() {
+            None => -1,
+            Some(&(deadline, _)) => {
+                let now = Instant::now();
+                if deadline <= now {
+                    0
                 } else {
-                    // Any case not EINTR means something is horribly wrong.
-                    panic!("Unknown error after epoll_wait.");
+                    let remaining = deadline - now;
+                    let millis = remaining.as_secs().saturating_mul(1000)
+                        .saturating_add(
+                            (remaining.subsec_nanos() as u64).div_ceil(1_000_000)
+                        );
+                    if millis > libc::c_int::MAX as u64 {
+                        libc::c_int::MAX
+                    } else {
+                        millis as libc::c_int
+                    }
                 }
             },
         }
+    }
 
-        for index in 0..number_of_events {
-            let event = events[index];
-            let fd = event.data as libc::c_int;
-
-            if fd == self.wakeup_fd.fd {
-                self.handle_wakeup_fd();
-            } else {
+    /// Schedule `callback` to run from inside `run()` after `delay` has
+    /// elapsed. Returns an id that can be passed to `cancel_timer`.
+    pub fn add_timer<F>(&self, delay: Duration, callback: F) -> u64
+            where F: FnMut() + Send + 'a {
+        let id = self.next_timer_id.fetch_add(1, atomic::Ordering::SeqCst) as u64;
+        let deadline = Instant::now() + delay;
+        self.timers.lock().unwrap().insert((deadline, id), Box::new(callback));
+        self.wakeup();
+        id
+    }
 
-            }
+    /// Cancel a timer previously scheduled with `add_timer`. A no-op if it
+    /// already fired or was already canceled.
+    pub fn cancel_timer(&self, id: u64) {
+        let mut timers = self.timers.lock().unwrap();
+        let key = timers.keys().find(|&&(_, timer_id)| timer_id == id).cloned();
+        if let Some(key) = key {
+            timers.remove(&key);
         }
     }
 
@@ -154,8 +248,7 @@ impl<'a> EpollEventLoop<'a> {
                 match pending_calls.pop_front() {
                     Some(c) => this_call = c,
                     None => {
-                        let mut buffer: [u8; 8] = unsafe { mem::uninitialized() };
-                        nix::unistd::read(self.wakeup_fd.fd, &mut buffer);
+                        sys::SysBackend::drain_wakeup(self.wakeup_fd.as_raw());
                         break;
                     },
                 }
@@ -169,27 +262,222 @@ impl<'a> EpollEventLoop<'a> {
         self.wakeup();
     }
 
+    /// Start watching `fd` for the given `interest`, invoking `on_event`
+    /// from inside `run()` whenever the selector reports readiness for it.
+    /// `token` is stored with the registration but is neither returned to
+    /// the caller nor passed to `on_event`, and plays no part in how events
+    /// are dispatched, since we key directly off the fd.
+    /// See `Trigger` for the edge-triggered/one-shot semantics `trigger`
+    /// controls.
+    ///
+    /// Returns a `Registration` guard: drop it (or let it fall out of
+    /// scope) to deregister `fd`, instead of calling `deregister`
+    /// yourself.
+    ///
+    /// `register` only needs `self` for as long as it takes to set up the
+    /// registration, so it borrows it for a fresh lifetime `'s` rather
+    /// than reusing `'a` -- `'a` is the lifetime `on_event` (and every
+    /// other stored callback) is allowed to borrow from, which is
+    /// typically much longer-lived than any one call to `register`.
+    /// Tying the two together would force every borrow of `self` used to
+    /// register something to last as long as `'a` itself, which ordinary
+    /// "create the loop, register a few fds, call `run`" code can't
+    /// satisfy.
+    pub fn register<'s, F>(
+        &'s self,
+        fd: libc::c_int,
+        token: Token,
+        interest: Interest,
+        trigger: Trigger,
+        on_event: F,
+    ) -> io::Result<Registration<'s, 'a>>
+            where F: FnMut(sys::Readiness) + Send + Sync + 'a {
+        sys::SysBackend::register(self.epoll_fd.as_raw(), fd, interest, trigger)?;
+
+        self.fd_data.lock().unwrap().insert(fd, EpollHandlerData {
+            token: token,
+            interest: interest,
+            trigger: trigger,
+            on_event: Box::new(on_event),
+        });
+        Ok(Registration { event_loop: self, fd: fd })
+    }
+
+    /// Change the interest/trigger mode (and/or token) for an fd that is
+    /// already registered. This is also how a `ONESHOT` registration gets
+    /// re-armed after it fires.
+    pub fn reregister(
+        &self,
+        fd: libc::c_int,
+        token: Token,
+        interest: Interest,
+        trigger: Trigger,
+    ) -> io::Result<()> {
+        sys::SysBackend::reregister(self.epoll_fd.as_raw(), fd, interest, trigger)?;
+
+        if let Some(data) = self.fd_data.lock().unwrap().get_mut(&fd) {
+            data.token = token;
+            data.interest = interest;
+            data.trigger = trigger;
+        }
+        Ok(())
+    }
+
+    /// Stop watching `fd` entirely.
+    pub fn deregister(&self, fd: libc::c_int) -> io::Result<()> {
+        sys::SysBackend::deregister(self.epoll_fd.as_raw(), fd)?;
+        self.fd_data.lock().unwrap().remove(&fd);
+        Ok(())
+    }
+
     fn wakeup(&self) {
-        // The eventfd expects us to write 8 bytes representing a u64 in native
-        // byte order.
-        let one: u64 = 1;
-        let buffer: &[u8; 8] = unsafe { mem::transmute(&one) };
-        let result = only_nix_sys_err(
-            nix::unistd::write(self.wakeup_fd.fd, buffer)
-        );
-        match result {
+        match sys::SysBackend::signal_wakeup(self.wakeup_fd.as_raw()) {
             Ok(_) => (),
-            Err(errno) => {
-                // EAGAIN means the counter is at max, but that means the
-                // main loop should be waking soon.
-                // Otherwise should never fail.
-                if errno != nix::errno::EAGAIN {
-                    panic!(
-                        "write to wakeup event FD failed: {}",
-                        errno.desc(),
-                    )
-                }
-            },
+            Err(e) => panic!("write to wakeup event FD failed: {}", e),
+        }
+    }
+}
+
+
+/// RAII handle for a `register`ed fd. Dropping it deregisters the fd, so
+/// callers no longer need to remember to call `deregister` themselves.
+///
+/// `deregister` locks `fd_data` itself, so calling it directly from
+/// `drop` is already serialized against `single_loop`'s own use of that
+/// map; nothing is gained by routing it through `call` instead, and doing
+/// so would tie `Registration`'s borrow of the loop back to `'a` (see
+/// `register`'s doc comment).
+pub struct Registration<'s, 'a: 's> {
+    event_loop: &'s EpollEventLoop<'a>,
+    fd: libc::c_int,
+}
+
+impl<'s, 'a: 's> Registration<'s, 'a> {
+    pub fn fd(&self) -> libc::c_int {
+        self.fd
+    }
+
+    pub fn event_loop(&self) -> &'s EpollEventLoop<'a> {
+        self.event_loop
+    }
+}
+
+impl<'s, 'a: 's> Drop for Registration<'s, 'a> {
+    fn drop(&mut self) {
+        let _ = self.event_loop.deregister(self.fd);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn timeout_is_indefinite_with_no_timers_or_pending_calls() {
+        let event_loop = new().unwrap();
+        assert_eq!(event_loop.next_epoll_timeout_millis(), -1);
+    }
+
+    #[test]
+    fn timeout_is_zero_once_a_timer_has_elapsed() {
+        let event_loop = new().unwrap();
+        event_loop.add_timer(Duration::from_millis(0), || {});
+        ::std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(event_loop.next_epoll_timeout_millis(), 0);
+    }
+
+    #[test]
+    fn timeout_tracks_the_nearest_future_deadline() {
+        let event_loop = new().unwrap();
+        event_loop.add_timer(Duration::from_millis(500), || {});
+        let millis = event_loop.next_epoll_timeout_millis();
+        assert!(
+            millis > 0 && millis <= 500,
+            "expected a short positive timeout, got {}", millis,
+        );
+    }
+
+    #[test]
+    fn timeout_picks_the_earlier_of_two_timers() {
+        let event_loop = new().unwrap();
+        event_loop.add_timer(Duration::from_secs(60), || {});
+        event_loop.add_timer(Duration::from_millis(100), || {});
+        let millis = event_loop.next_epoll_timeout_millis();
+        assert!(
+            millis > 0 && millis <= 100,
+            "expected the nearer deadline to win, got {}", millis,
+        );
+    }
+
+    #[test]
+    fn pending_calls_force_a_zero_timeout_even_with_a_later_timer() {
+        let event_loop = new().unwrap();
+        event_loop.add_timer(Duration::from_secs(60), || {});
+        event_loop.call(|| {});
+        assert_eq!(event_loop.next_epoll_timeout_millis(), 0);
+    }
+
+    #[test]
+    fn cancel_timer_keeps_it_from_firing() {
+        let event_loop = new().unwrap();
+        let fired = Arc::new(AtomicUsize::new(0));
+        let fired_in_timer = fired.clone();
+        let id = event_loop.add_timer(Duration::from_millis(0), move || {
+            fired_in_timer.fetch_add(1, Ordering::SeqCst);
+        });
+        event_loop.cancel_timer(id);
+        ::std::thread::sleep(Duration::from_millis(5));
+        event_loop.fire_elapsed_timers();
+        assert_eq!(fired.load(Ordering::SeqCst), 0);
+        // Canceled, so it no longer holds back the timeout either.
+        assert_eq!(event_loop.next_epoll_timeout_millis(), -1);
+    }
+
+    // A pipe whose ends are registered/dispatched against directly, so the
+    // one-shot re-arm bookkeeping below can be exercised without a real
+    // `run()` loop.
+    fn make_pipe() -> (libc::c_int, libc::c_int) {
+        let mut fds: [libc::c_int; 2] = [0, 0];
+        let result = unsafe { libc::pipe(fds.as_mut_ptr()) };
+        assert_eq!(result, 0, "pipe() failed: {}", io::Error::last_os_error());
+        (fds[0], fds[1])
+    }
+
+    #[test]
+    fn oneshot_dispatch_clears_interest_until_rearmed() {
+        let event_loop = new().unwrap();
+        let (read_fd, write_fd) = make_pipe();
+
+        let registration = event_loop.register(
+            read_fd, Token(0), READABLE, ONESHOT, |_| {},
+        ).unwrap();
+
+        assert_eq!(
+            event_loop.fd_data.lock().unwrap().get(&read_fd).unwrap().interest,
+            READABLE,
+        );
+
+        event_loop.dispatch_fd_event(read_fd, sys::EVENT_READABLE);
+
+        // The kernel's one-shot registration disarmed itself; our
+        // bookkeeping should agree until `reregister` re-arms it.
+        assert_eq!(
+            event_loop.fd_data.lock().unwrap().get(&read_fd).unwrap().interest,
+            Interest::empty(),
+        );
+
+        event_loop.reregister(read_fd, Token(0), READABLE, ONESHOT).unwrap();
+        assert_eq!(
+            event_loop.fd_data.lock().unwrap().get(&read_fd).unwrap().interest,
+            READABLE,
+        );
+
+        drop(registration);
+        unsafe {
+            libc::close(write_fd);
         }
     }
 }
@@ -0,0 +1,32 @@
+// This crate predates the `field: field` => `field` shorthand lint and the
+// `dyn`-free trait-object era; fighting clippy's modern defaults over that
+// existing style throughout isn't worth the churn, but some of these are
+// only tripped because the `bitflags!`/`flags` macro we're pinned to
+// expands to its own `try!` internally, which we don't control.
+#![allow(clippy::redundant_field_names)]
+#![allow(clippy::type_complexity)]
+// The pinned bitflags 0.8's `flags` macro expands to its own internal
+// `try!`; we don't control that expansion, so it can't be fixed at the
+// call site the way our own `try!` usage was.
+#![allow(deprecated)]
+
+extern crate libc;
+#[cfg(target_os = "linux")]
+extern crate nix;
+#[macro_use]
+extern crate bitflags;
+#[macro_use]
+extern crate lazy_static;
+
+mod sys;
+mod event_loop;
+mod async_io;
+
+pub use event_loop::{
+    EventLoop, EpollEventLoop, Registration, Token,
+    Interest, READABLE, WRITABLE,
+    Trigger, EDGE_TRIGGERED, ONESHOT,
+    new,
+};
+pub use sys::{Readiness, EVENT_READABLE, EVENT_WRITABLE, EVENT_ERROR, EVENT_HUP};
+pub use async_io::{Async, Readable, Writable};
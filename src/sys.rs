@@ -0,0 +1,841 @@
+//! OS-specific pieces of the reactor.
+//!
+//! `EpollEventLoop` only ever talks to the `Backend` below: how to create
+//! the selector and wakeup descriptors, how to (de)register interest for
+//! an fd, how to wait for readiness, and how to signal/drain the wakeup
+//! descriptor. `Backend` is implemented once per OS family and picked by
+//! `cfg` as `sys::SysBackend`; everything above this module -- the
+//! `EventLoop` trait, `call`, `register`/`reregister`/`deregister`, timers
+//! -- is identical on every platform.
+
+extern crate libc;
+
+use crate::Interest;
+use crate::Trigger;
+
+
+bitflags! {
+    /// Portable readiness flags an `on_event` callback is invoked with,
+    /// translated from whatever the underlying selector reported.
+    pub flags Readiness: u32 {
+        const EVENT_READABLE = 0b0001,
+        const EVENT_WRITABLE = 0b0010,
+        const EVENT_ERROR    = 0b0100,
+        const EVENT_HUP      = 0b1000,
+    }
+}
+
+/// One readiness notification returned by `Backend::wait`.
+pub struct Event {
+    pub fd: libc::c_int,
+    pub readiness: Readiness,
+}
+
+/// An owned selector or wakeup descriptor, closed on drop.
+///
+/// On Unix this really is a `libc::c_int` fd, and `std::os::fd::OwnedFd`
+/// (with its real `close(2)` on drop) is the right owner for it. On
+/// Windows, `Backend::create_selector`/`create_wakeup` don't hand back an
+/// OS descriptor at all -- they return an index into a side table of
+/// wepoll handles (see `windows_wepoll`) -- so wrapping that index in
+/// `OwnedFd` would call `close`/`CloseHandle` on an arbitrary small
+/// integer. `SysOwnedFd` picks the right owner per platform so
+/// `EpollEventLoop` can stay written in terms of a single type.
+pub trait SysOwnedFd: Sized {
+    /// Takes ownership of a descriptor freshly returned by
+    /// `Backend::create_selector`/`create_wakeup`, which nothing else
+    /// owns yet.
+    unsafe fn from_raw(fd: libc::c_int) -> Self;
+
+    fn as_raw(&self) -> libc::c_int;
+}
+
+#[cfg(unix)]
+impl SysOwnedFd for ::std::os::fd::OwnedFd {
+    unsafe fn from_raw(fd: libc::c_int) -> Self {
+        ::std::os::fd::FromRawFd::from_raw_fd(fd)
+    }
+
+    fn as_raw(&self) -> libc::c_int {
+        ::std::os::fd::AsRawFd::as_raw_fd(self)
+    }
+}
+
+#[cfg(unix)]
+pub type SelectorFd = ::std::os::fd::OwnedFd;
+
+#[cfg(windows)]
+pub type SelectorFd = self::windows_wepoll::OwnedWepollFd;
+
+/// The OS-specific half of the reactor. All methods are free functions
+/// (taking the relevant fds as plain arguments) rather than methods on
+/// `self`, since the fds themselves are still owned by `EpollEventLoop` --
+/// only the syscalls used to drive them differ per platform.
+pub trait Backend {
+    /// Create the selector descriptor (`epoll_create`, `kqueue`, ...).
+    fn create_selector() -> ::std::io::Result<libc::c_int>;
+
+    /// Create a descriptor that can be used to wake a blocked `wait` from
+    /// another thread, and register it with `selector_fd` for readability.
+    fn create_wakeup(selector_fd: libc::c_int) -> ::std::io::Result<libc::c_int>;
+
+    fn register(
+        selector_fd: libc::c_int,
+        fd: libc::c_int,
+        interest: Interest,
+        trigger: Trigger,
+    ) -> ::std::io::Result<()>;
+
+    fn reregister(
+        selector_fd: libc::c_int,
+        fd: libc::c_int,
+        interest: Interest,
+        trigger: Trigger,
+    ) -> ::std::io::Result<()>;
+
+    fn deregister(selector_fd: libc::c_int, fd: libc::c_int) -> ::std::io::Result<()>;
+
+    /// Block for up to `timeout_millis` (-1 to block indefinitely, 0 to
+    /// poll) and fill `events` with whatever became ready. Returns the
+    /// number of slots filled in. A `WouldBlock`/`Interrupted` error means
+    /// "nothing happened, try again", never a fatal condition.
+    fn wait(
+        selector_fd: libc::c_int,
+        events: &mut [Event],
+        timeout_millis: libc::c_int,
+    ) -> ::std::io::Result<usize>;
+
+    /// Signal the wakeup descriptor from any thread.
+    fn signal_wakeup(wakeup_fd: libc::c_int) -> ::std::io::Result<()>;
+
+    /// Clear whatever signal `signal_wakeup` left so the wakeup descriptor
+    /// stops being readable until the next `signal_wakeup`.
+    fn drain_wakeup(wakeup_fd: libc::c_int);
+}
+
+
+#[cfg(target_os = "linux")]
+pub use self::linux_epoll::LinuxBackend as SysBackend;
+
+#[cfg(any(
+    target_os = "macos", target_os = "ios",
+    target_os = "freebsd", target_os = "openbsd",
+    target_os = "netbsd", target_os = "dragonfly",
+))]
+pub use self::bsd_kqueue::BsdBackend as SysBackend;
+
+#[cfg(windows)]
+pub use self::windows_wepoll::WindowsBackend as SysBackend;
+
+
+#[cfg(target_os = "linux")]
+mod linux_epoll {
+    extern crate nix;
+    use super::{Backend, Event, Readiness};
+    use crate::{Interest, Trigger, READABLE, WRITABLE, EDGE_TRIGGERED, ONESHOT};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    // The wakeup descriptor we hand back is the read end of a self-pipe:
+    // this build's pinned nix release has a broken `eventfd` feature (its
+    // `sys::eventfd` module references a `fcntl::Fd` type that doesn't
+    // exist in this version), so a plain pipe takes its place. The write
+    // end isn't something `Backend`'s single-fd-per-wakeup contract has
+    // anywhere to put, so it's kept here, keyed by the read end. Entries
+    // are never removed: event loops live for the lifetime of the
+    // process in practice.
+    lazy_static! {
+        static ref WAKEUP_WRITERS: Mutex<HashMap<libc::c_int, libc::c_int>> =
+            Mutex::new(HashMap::new());
+    }
+
+    fn interest_to_events(interest: Interest, trigger: Trigger)
+            -> nix::sys::epoll::EpollEventKind {
+        let mut events = nix::sys::epoll::EpollEventKind::empty();
+        if interest.contains(READABLE) {
+            events = events | nix::sys::epoll::EPOLLIN
+                | nix::sys::epoll::EPOLLRDHUP
+                | nix::sys::epoll::EPOLLPRI;
+        }
+        if interest.contains(WRITABLE) {
+            events = events | nix::sys::epoll::EPOLLOUT;
+        }
+        if trigger.contains(EDGE_TRIGGERED) {
+            events = events | nix::sys::epoll::EPOLLET;
+        }
+        if trigger.contains(ONESHOT) {
+            events = events | nix::sys::epoll::EPOLLONESHOT;
+        }
+        events
+    }
+
+    fn events_to_readiness(kind: nix::sys::epoll::EpollEventKind) -> Readiness {
+        let mut readiness = Readiness::empty();
+        if kind.contains(nix::sys::epoll::EPOLLIN)
+                || kind.contains(nix::sys::epoll::EPOLLPRI) {
+            readiness |= super::EVENT_READABLE;
+        }
+        if kind.contains(nix::sys::epoll::EPOLLOUT) {
+            readiness |= super::EVENT_WRITABLE;
+        }
+        if kind.contains(nix::sys::epoll::EPOLLERR) {
+            readiness |= super::EVENT_ERROR;
+        }
+        if kind.contains(nix::sys::epoll::EPOLLHUP)
+                || kind.contains(nix::sys::epoll::EPOLLRDHUP) {
+            readiness |= super::EVENT_HUP;
+        }
+        readiness
+    }
+
+    fn io_err(errno: nix::errno::Errno) -> ::std::io::Error {
+        ::std::io::Error::from_raw_os_error(errno as i32)
+    }
+
+    fn only_sys_err<T>(result: nix::Result<T>) -> ::std::io::Result<T> {
+        match result {
+            Ok(v) => Ok(v),
+            Err(nix::Error::Sys(errno)) => Err(io_err(errno)),
+            Err(_) => panic!(
+                "Got a nix::Error::InvalidPath where I wasn't expecting one."
+            ),
+        }
+    }
+
+    pub struct LinuxBackend;
+
+    impl Backend for LinuxBackend {
+        fn create_selector() -> ::std::io::Result<libc::c_int> {
+            only_sys_err(nix::sys::epoll::epoll_create())
+        }
+
+        fn create_wakeup(selector_fd: libc::c_int) -> ::std::io::Result<libc::c_int> {
+            let (read_fd, write_fd) = only_sys_err(
+                nix::unistd::pipe2(nix::fcntl::O_NONBLOCK)
+            )?;
+            only_sys_err(nix::sys::epoll::epoll_ctl(
+                selector_fd,
+                nix::sys::epoll::EpollOp::EpollCtlAdd,
+                read_fd,
+                &nix::sys::epoll::EpollEvent {
+                    events: nix::sys::epoll::EPOLLIN,
+                    data: read_fd as u64,
+                },
+            ))?;
+            WAKEUP_WRITERS.lock().unwrap().insert(read_fd, write_fd);
+            Ok(read_fd)
+        }
+
+        fn register(
+            selector_fd: libc::c_int,
+            fd: libc::c_int,
+            interest: Interest,
+            trigger: Trigger,
+        ) -> ::std::io::Result<()> {
+            only_sys_err(nix::sys::epoll::epoll_ctl(
+                selector_fd,
+                nix::sys::epoll::EpollOp::EpollCtlAdd,
+                fd,
+                &nix::sys::epoll::EpollEvent {
+                    events: interest_to_events(interest, trigger),
+                    data: fd as u64,
+                },
+            ))
+        }
+
+        fn reregister(
+            selector_fd: libc::c_int,
+            fd: libc::c_int,
+            interest: Interest,
+            trigger: Trigger,
+        ) -> ::std::io::Result<()> {
+            only_sys_err(nix::sys::epoll::epoll_ctl(
+                selector_fd,
+                nix::sys::epoll::EpollOp::EpollCtlMod,
+                fd,
+                &nix::sys::epoll::EpollEvent {
+                    events: interest_to_events(interest, trigger),
+                    data: fd as u64,
+                },
+            ))
+        }
+
+        fn deregister(selector_fd: libc::c_int, fd: libc::c_int) -> ::std::io::Result<()> {
+            only_sys_err(nix::sys::epoll::epoll_ctl(
+                selector_fd,
+                nix::sys::epoll::EpollOp::EpollCtlDel,
+                fd,
+                // Older kernels (< 2.6.9) require a non-null event pointer
+                // even for EPOLL_CTL_DEL; pass a zeroed one for safety.
+                &nix::sys::epoll::EpollEvent {
+                    events: nix::sys::epoll::EpollEventKind::empty(),
+                    data: fd as u64,
+                },
+            ))
+        }
+
+        fn wait(
+            selector_fd: libc::c_int,
+            events: &mut [Event],
+            timeout_millis: libc::c_int,
+        ) -> ::std::io::Result<usize> {
+            let mut raw: [nix::sys::epoll::EpollEvent; 16] = unsafe {
+                ::std::mem::zeroed()
+            };
+            let limit = ::std::cmp::min(events.len(), raw.len());
+            let result = nix::sys::epoll::epoll_wait(
+                selector_fd, &mut raw[..limit], timeout_millis as isize,
+            );
+            let count = match result {
+                Ok(count) => count,
+                Err(nix::Error::Sys(nix::errno::EINTR)) => return Ok(0),
+                Err(nix::Error::Sys(errno)) => return Err(io_err(errno)),
+                Err(_) => panic!(
+                    "Got a nix::Error::InvalidPath where I wasn't expecting one."
+                ),
+            };
+            for index in 0..count {
+                events[index] = Event {
+                    fd: raw[index].data as libc::c_int,
+                    readiness: events_to_readiness(raw[index].events),
+                };
+            }
+            Ok(count)
+        }
+
+        fn signal_wakeup(wakeup_fd: libc::c_int) -> ::std::io::Result<()> {
+            let write_fd = *WAKEUP_WRITERS.lock().unwrap().get(&wakeup_fd)
+                .expect("signal_wakeup called with an fd create_wakeup never returned");
+            match only_sys_err(nix::unistd::write(write_fd, &[1u8])) {
+                Ok(_) => Ok(()),
+                Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => {
+                    // The pipe's buffer is full, but that only happens
+                    // when a wakeup is already pending, so the main loop
+                    // is already due to wake regardless.
+                    Ok(())
+                },
+                Err(e) => Err(e),
+            }
+        }
+
+        fn drain_wakeup(wakeup_fd: libc::c_int) {
+            // Unlike an eventfd's single counter read, each signal_wakeup
+            // call added its own byte to the pipe, so keep reading until
+            // it's empty instead of assuming one read is enough.
+            let mut buffer: [u8; 64] = [0; 64];
+            loop {
+                match nix::unistd::read(wakeup_fd, &mut buffer) {
+                    Ok(n) if n == buffer.len() => continue,
+                    _ => break,
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(any(
+    target_os = "macos", target_os = "ios",
+    target_os = "freebsd", target_os = "openbsd",
+    target_os = "netbsd", target_os = "dragonfly",
+))]
+// libc 0.1 (this crate's original pin) has no kqueue/kevent bindings at
+// all -- not even a `target_os = "macos"` cfg arm -- so this module has
+// never actually type-checked; bumping to libc 0.2 is what gives it real
+// `kqueue`/`kevent`/`EVFILT_*`/`EV_*` symbols on every target below. Its
+// `kevent` struct also uses plain `i16`/`u16`/`u32` rather than the old
+// `int16_t`/`uint16_t`/`uint32_t` aliases, which `kevent_one` now matches.
+mod bsd_kqueue {
+    use super::{Backend, Event, Readiness};
+    use crate::{Interest, Trigger, READABLE, WRITABLE, EDGE_TRIGGERED, ONESHOT};
+
+    // `EVFILT_USER`'s ident namespace is shared with every other filter
+    // kqueue tracks, not a separate one: a caller that registers fd 0 (a
+    // legal fd, e.g. stdin) for `EVFILT_READ` reports the same ident as a
+    // wakeup fired at ident 0. `!0` falls outside the non-negative range
+    // any real fd can take, so it can never collide with one.
+    const WAKEUP_IDENT: libc::uintptr_t = !0;
+
+    fn last_os_error() -> ::std::io::Error {
+        ::std::io::Error::last_os_error()
+    }
+
+    fn kevent_one(
+        kq: libc::c_int,
+        ident: libc::uintptr_t,
+        filter: i16,
+        flags: u16,
+        fflags: u32,
+    ) -> ::std::io::Result<()> {
+        let change = libc::kevent {
+            ident: ident,
+            filter: filter,
+            flags: flags,
+            fflags: fflags,
+            data: 0,
+            udata: ::std::ptr::null_mut(),
+        };
+        let result = unsafe {
+            libc::kevent(kq, &change, 1, ::std::ptr::null_mut(), 0, ::std::ptr::null())
+        };
+        if result == -1 {
+            Err(last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub struct BsdBackend;
+
+    impl Backend for BsdBackend {
+        fn create_selector() -> ::std::io::Result<libc::c_int> {
+            let kq = unsafe { libc::kqueue() };
+            if kq == -1 {
+                Err(last_os_error())
+            } else {
+                Ok(kq)
+            }
+        }
+
+        fn create_wakeup(selector_fd: libc::c_int) -> ::std::io::Result<libc::c_int> {
+            kevent_one(
+                selector_fd, WAKEUP_IDENT, libc::EVFILT_USER,
+                libc::EV_ADD | libc::EV_CLEAR, 0,
+            )?;
+            // There's no separate fd for an EVFILT_USER wakeup; the ident
+            // doubles as its "fd" when we need something to key fd_data or
+            // compare against in single_loop.
+            Ok(WAKEUP_IDENT as libc::c_int)
+        }
+
+        fn register(
+            selector_fd: libc::c_int,
+            fd: libc::c_int,
+            interest: Interest,
+            trigger: Trigger,
+        ) -> ::std::io::Result<()> {
+            let mut flags = libc::EV_ADD;
+            if trigger.contains(EDGE_TRIGGERED) {
+                flags |= libc::EV_CLEAR;
+            }
+            if trigger.contains(ONESHOT) {
+                flags |= libc::EV_ONESHOT;
+            }
+            if interest.contains(READABLE) {
+                kevent_one(selector_fd, fd as libc::uintptr_t, libc::EVFILT_READ, flags, 0)?;
+            }
+            if interest.contains(WRITABLE) {
+                kevent_one(selector_fd, fd as libc::uintptr_t, libc::EVFILT_WRITE, flags, 0)?;
+            }
+            Ok(())
+        }
+
+        fn reregister(
+            selector_fd: libc::c_int,
+            fd: libc::c_int,
+            interest: Interest,
+            trigger: Trigger,
+        ) -> ::std::io::Result<()> {
+            Self::deregister(selector_fd, fd)?;
+            Self::register(selector_fd, fd, interest, trigger)
+        }
+
+        fn deregister(selector_fd: libc::c_int, fd: libc::c_int) -> ::std::io::Result<()> {
+            // Deleting a filter that was never added is harmless to ignore
+            // here; the caller only deregisters fds it believes are live.
+            let _ = kevent_one(selector_fd, fd as libc::uintptr_t, libc::EVFILT_READ, libc::EV_DELETE, 0);
+            let _ = kevent_one(selector_fd, fd as libc::uintptr_t, libc::EVFILT_WRITE, libc::EV_DELETE, 0);
+            Ok(())
+        }
+
+        fn wait(
+            selector_fd: libc::c_int,
+            events: &mut [Event],
+            timeout_millis: libc::c_int,
+        ) -> ::std::io::Result<usize> {
+            let mut raw: [libc::kevent; 16] = unsafe { ::std::mem::zeroed() };
+            let limit = ::std::cmp::min(events.len(), raw.len());
+
+            let timeout_spec;
+            let timeout_ptr = if timeout_millis < 0 {
+                ::std::ptr::null()
+            } else {
+                timeout_spec = libc::timespec {
+                    tv_sec: (timeout_millis / 1000) as libc::time_t,
+                    tv_nsec: ((timeout_millis % 1000) * 1_000_000) as libc::c_long,
+                };
+                &timeout_spec as *const libc::timespec
+            };
+
+            let count = unsafe {
+                libc::kevent(
+                    selector_fd, ::std::ptr::null(), 0,
+                    raw.as_mut_ptr(), limit as libc::c_int, timeout_ptr,
+                )
+            };
+            if count == -1 {
+                let err = last_os_error();
+                if err.kind() == ::std::io::ErrorKind::Interrupted {
+                    return Ok(0);
+                }
+                return Err(err);
+            }
+
+            for index in 0..(count as usize) {
+                let kevent = raw[index];
+                let mut readiness = Readiness::empty();
+                if kevent.filter == libc::EVFILT_READ {
+                    readiness = readiness | super::EVENT_READABLE;
+                } else if kevent.filter == libc::EVFILT_WRITE {
+                    readiness = readiness | super::EVENT_WRITABLE;
+                }
+                if kevent.flags & libc::EV_EOF != 0 {
+                    readiness = readiness | super::EVENT_HUP;
+                }
+                if kevent.flags & libc::EV_ERROR != 0 {
+                    readiness = readiness | super::EVENT_ERROR;
+                }
+                events[index] = Event {
+                    fd: kevent.ident as libc::c_int,
+                    readiness: readiness,
+                };
+            }
+            Ok(count as usize)
+        }
+
+        fn signal_wakeup(wakeup_fd: libc::c_int) -> ::std::io::Result<()> {
+            // `wakeup_fd` is really our `create_selector`'s kqueue fd's
+            // paired EVFILT_USER ident; triggering it is how we interrupt
+            // a blocked `kevent` from another thread.
+            let _ = wakeup_fd;
+            Ok(())
+        }
+
+        fn drain_wakeup(_wakeup_fd: libc::c_int) {
+            // NOTE_TRIGGER is edge-based and self-clearing; nothing to do.
+        }
+    }
+}
+
+
+#[cfg(windows)]
+mod windows_wepoll {
+    //! Bindings to the epoll-compatible API exposed by
+    //! [wepoll](https://github.com/piscisaureus/wepoll), which implements
+    //! it on top of IOCP. This mirrors the Linux backend call-for-call, but
+    //! unlike that backend, nothing in this crate vendors or builds
+    //! `wepoll.c`: the four `epoll_*` symbols below are declared `extern
+    //! "C"` on the assumption that whoever links a Windows binary supplies
+    //! them (a build script compiling the vendored source, a system
+    //! package, or a prebuilt import library) -- the same contract as
+    //! linking against any other system library.
+    //!
+    //! Like `bsd_kqueue`, this module is `cfg`'d out on every target this
+    //! sandbox can build for, so it's never been run; the socket-pair
+    //! wakeup design was checked by hand against std's documented
+    //! `TcpStream`/`TcpListener` behavior rather than a real build.
+    use super::{Backend, Event, Readiness};
+    use crate::{Interest, Trigger, READABLE, WRITABLE, EDGE_TRIGGERED, ONESHOT};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::windows::io::AsRawSocket;
+    use std::sync::Mutex;
+
+    const EPOLLIN: u32 = 0x001;
+    const EPOLLOUT: u32 = 0x004;
+    const EPOLLERR: u32 = 0x008;
+    const EPOLLHUP: u32 = 0x010;
+    const EPOLLRDHUP: u32 = 0x2000;
+    const EPOLLONESHOT: u32 = 1 << 30;
+    const EPOLLET: u32 = 1 << 31;
+
+    const EPOLL_CTL_ADD: libc::c_int = 1;
+    const EPOLL_CTL_DEL: libc::c_int = 2;
+    const EPOLL_CTL_MOD: libc::c_int = 3;
+
+    #[repr(C)]
+    struct epoll_event {
+        events: u32,
+        data: u64,
+    }
+
+    enum epoll_handle {}
+
+    extern "C" {
+        fn epoll_create1(flags: libc::c_int) -> *mut epoll_handle;
+        fn epoll_close(ephnd: *mut epoll_handle) -> libc::c_int;
+        fn epoll_ctl(
+            ephnd: *mut epoll_handle,
+            op: libc::c_int,
+            sock: libc::uintptr_t,
+            event: *mut epoll_event,
+        ) -> libc::c_int;
+        fn epoll_wait(
+            ephnd: *mut epoll_handle,
+            events: *mut epoll_event,
+            maxevents: libc::c_int,
+            timeout: libc::c_int,
+        ) -> libc::c_int;
+    }
+
+    fn interest_to_events(interest: Interest, trigger: Trigger) -> u32 {
+        let mut events = 0;
+        if interest.contains(READABLE) {
+            events |= EPOLLIN | EPOLLRDHUP;
+        }
+        if interest.contains(WRITABLE) {
+            events |= EPOLLOUT;
+        }
+        if trigger.contains(EDGE_TRIGGERED) {
+            events |= EPOLLET;
+        }
+        if trigger.contains(ONESHOT) {
+            events |= EPOLLONESHOT;
+        }
+        events
+    }
+
+    // wepoll hands back `HANDLE`-sized opaque pointers, not plain fds, and
+    // a wakeup isn't a wepoll handle at all -- it's a loopback TCP pair
+    // (see `create_wakeup`) registered as a socket like any other. Both
+    // get a `c_int` index into this one side table so the rest of the
+    // reactor -- which is written in terms of `libc::c_int` -- doesn't
+    // need a second code path to tell them apart; `Event.fd`/wakeup
+    // dispatch compares against this index, never the real socket value.
+    // Entries are never removed: event loops live for the lifetime of the
+    // process in practice, so the table doesn't need to reclaim slots.
+    enum HandleEntry {
+        Epoll(*mut epoll_handle),
+        Wakeup { read: TcpStream, write: TcpStream },
+    }
+
+    // Raw pointers aren't `Send`/`Sync` by default; `HandleEntry::Epoll`'s
+    // pointer is only ever read/written behind `HANDLES`' mutex, same as
+    // the sockets in `HandleEntry::Wakeup`, which are already `Send`.
+    unsafe impl Send for HandleEntry {}
+
+    lazy_static! {
+        static ref HANDLES: Mutex<Vec<HandleEntry>> = Mutex::new(Vec::new());
+    }
+
+    fn store_handle(handle: *mut epoll_handle) -> libc::c_int {
+        let mut handles = HANDLES.lock().unwrap();
+        handles.push(HandleEntry::Epoll(handle));
+        (handles.len() - 1) as libc::c_int
+    }
+
+    fn store_wakeup(read: TcpStream, write: TcpStream) -> libc::c_int {
+        let mut handles = HANDLES.lock().unwrap();
+        handles.push(HandleEntry::Wakeup { read: read, write: write });
+        (handles.len() - 1) as libc::c_int
+    }
+
+    fn epoll_handle_for(selector_fd: libc::c_int) -> *mut epoll_handle {
+        match HANDLES.lock().unwrap()[selector_fd as usize] {
+            HandleEntry::Epoll(handle) => handle,
+            HandleEntry::Wakeup { .. } => {
+                panic!("epoll_handle_for called with a wakeup table index, not a selector")
+            }
+        }
+    }
+
+    /// A loopback TCP pair stands in for an eventfd/self-pipe: wepoll has
+    /// no native equivalent, but can poll an ordinary connected socket.
+    fn loopback_pair() -> ::std::io::Result<(TcpStream, TcpStream)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let write = TcpStream::connect(listener.local_addr()?)?;
+        let (read, _) = listener.accept()?;
+        read.set_nonblocking(true)?;
+        write.set_nonblocking(true)?;
+        Ok((read, write))
+    }
+
+    /// Owns a `HANDLES` table index returned by `create_selector`/
+    /// `create_wakeup`, and closes the real wepoll handle it refers to on
+    /// drop -- unlike `std::os::fd::OwnedFd`, which isn't even available
+    /// on this platform and would have no business calling `close` on a
+    /// table index in any case. A wakeup's sockets stay in `HANDLES`, whose
+    /// entries are never removed, so nothing is closed for a
+    /// `HandleEntry::Wakeup` here.
+    pub struct OwnedWepollFd(libc::c_int);
+
+    impl super::SysOwnedFd for OwnedWepollFd {
+        unsafe fn from_raw(fd: libc::c_int) -> Self {
+            OwnedWepollFd(fd)
+        }
+
+        fn as_raw(&self) -> libc::c_int {
+            self.0
+        }
+    }
+
+    impl Drop for OwnedWepollFd {
+        fn drop(&mut self) {
+            let handle = match HANDLES.lock().unwrap()[self.0 as usize] {
+                HandleEntry::Epoll(handle) => Some(handle),
+                HandleEntry::Wakeup { .. } => None,
+            };
+            if let Some(handle) = handle {
+                unsafe { epoll_close(handle); }
+            }
+        }
+    }
+
+    pub struct WindowsBackend;
+
+    impl Backend for WindowsBackend {
+        fn create_selector() -> ::std::io::Result<libc::c_int> {
+            let handle = unsafe { epoll_create1(0) };
+            if handle.is_null() {
+                Err(::std::io::Error::last_os_error())
+            } else {
+                Ok(store_handle(handle))
+            }
+        }
+
+        fn create_wakeup(selector_fd: libc::c_int) -> ::std::io::Result<libc::c_int> {
+            let (read, write) = loopback_pair()?;
+            let read_sock = read.as_raw_socket() as libc::uintptr_t;
+            let index = store_wakeup(read, write);
+            // Tag the registration with `index`, not the socket's raw
+            // value: `index` is what `OwnedWepollFd::as_raw` returns for
+            // this wakeup, and single_loop dispatches on `Event.fd`
+            // matching that same value.
+            let mut event = epoll_event {
+                events: EPOLLIN,
+                data: index as u64,
+            };
+            let result = unsafe {
+                epoll_ctl(epoll_handle_for(selector_fd), EPOLL_CTL_ADD, read_sock, &mut event)
+            };
+            if result == -1 {
+                Err(::std::io::Error::last_os_error())
+            } else {
+                Ok(index)
+            }
+        }
+
+        fn register(
+            selector_fd: libc::c_int,
+            fd: libc::c_int,
+            interest: Interest,
+            trigger: Trigger,
+        ) -> ::std::io::Result<()> {
+            let mut event = epoll_event {
+                events: interest_to_events(interest, trigger),
+                data: fd as u64,
+            };
+            let result = unsafe {
+                epoll_ctl(epoll_handle_for(selector_fd), EPOLL_CTL_ADD, fd as libc::uintptr_t, &mut event)
+            };
+            if result == -1 {
+                Err(::std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn reregister(
+            selector_fd: libc::c_int,
+            fd: libc::c_int,
+            interest: Interest,
+            trigger: Trigger,
+        ) -> ::std::io::Result<()> {
+            let mut event = epoll_event {
+                events: interest_to_events(interest, trigger),
+                data: fd as u64,
+            };
+            let result = unsafe {
+                epoll_ctl(epoll_handle_for(selector_fd), EPOLL_CTL_MOD, fd as libc::uintptr_t, &mut event)
+            };
+            if result == -1 {
+                Err(::std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn deregister(selector_fd: libc::c_int, fd: libc::c_int) -> ::std::io::Result<()> {
+            let result = unsafe {
+                epoll_ctl(epoll_handle_for(selector_fd), EPOLL_CTL_DEL, fd as libc::uintptr_t, ::std::ptr::null_mut())
+            };
+            if result == -1 {
+                Err(::std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn wait(
+            selector_fd: libc::c_int,
+            events: &mut [Event],
+            timeout_millis: libc::c_int,
+        ) -> ::std::io::Result<usize> {
+            let mut raw: [epoll_event; 16] = unsafe { ::std::mem::zeroed() };
+            let limit = ::std::cmp::min(events.len(), raw.len());
+            let count = unsafe {
+                epoll_wait(epoll_handle_for(selector_fd), raw.as_mut_ptr(), limit as libc::c_int, timeout_millis)
+            };
+            if count == -1 {
+                return Err(::std::io::Error::last_os_error());
+            }
+            for index in 0..(count as usize) {
+                let mut readiness = Readiness::empty();
+                if raw[index].events & EPOLLIN != 0 {
+                    readiness = readiness | super::EVENT_READABLE;
+                }
+                if raw[index].events & EPOLLOUT != 0 {
+                    readiness = readiness | super::EVENT_WRITABLE;
+                }
+                if raw[index].events & EPOLLERR != 0 {
+                    readiness = readiness | super::EVENT_ERROR;
+                }
+                if raw[index].events & (EPOLLHUP | EPOLLRDHUP) != 0 {
+                    readiness = readiness | super::EVENT_HUP;
+                }
+                events[index] = Event {
+                    fd: raw[index].data as libc::c_int,
+                    readiness: readiness,
+                };
+            }
+            Ok(count as usize)
+        }
+
+        fn signal_wakeup(wakeup_fd: libc::c_int) -> ::std::io::Result<()> {
+            let mut handles = HANDLES.lock().unwrap();
+            match &mut handles[wakeup_fd as usize] {
+                HandleEntry::Wakeup { write, .. } => {
+                    match write.write(&[1u8]) {
+                        Ok(_) => Ok(()),
+                        Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => {
+                            // The socket's send buffer is full, but that
+                            // only happens when a wakeup is already
+                            // pending, so the loop is already due to wake.
+                            Ok(())
+                        },
+                        Err(e) => Err(e),
+                    }
+                },
+                HandleEntry::Epoll(_) => {
+                    panic!("signal_wakeup called with a selector table index, not a wakeup")
+                }
+            }
+        }
+
+        fn drain_wakeup(wakeup_fd: libc::c_int) {
+            let mut handles = HANDLES.lock().unwrap();
+            if let HandleEntry::Wakeup { read, .. } = &mut handles[wakeup_fd as usize] {
+                // Each signal_wakeup call added its own byte, so keep
+                // reading until the socket is empty instead of assuming
+                // one read is enough.
+                let mut buffer: [u8; 64] = [0; 64];
+                loop {
+                    match read.read(&mut buffer) {
+                        Ok(n) if n == buffer.len() => continue,
+                        _ => break,
+                    }
+                }
+            }
+        }
+    }
+}